@@ -1,16 +1,44 @@
 //! Process `.env.vibe` template files for worktrees.
 //!
 //! This module handles template processing for environment files, supporting:
-//! - `{{ auto_port() }}` - Automatic port assignment
+//! - `{{ auto_port() }}` - Automatic port assignment, optionally constrained
+//!   to a bounded range (`auto_port(8000-8099)`) or a named pool
+//!   (`auto_port(pool="frontend")`) configured via `port_pools`
 //! - `{{ branch() }}` - Branch name substitution
+//! - `{{ cfg(...) }} ... {{ endcfg }}` - Conditional blocks, plus the inline
+//!   `{{ cfg(...) ? "A" : "B" }}` ternary form
+//! - `{{ slug(EXPR) }}` - Sanitize `EXPR` into `[a-z0-9-]` form
+//! - `{{ uuid() }}` - A fresh v4 UUID
+//! - `{{ env(NAME | default) }}` - Read from the host environment, with a fallback
+//! - `{{ port_from(OTHER_VAR) }}` - Reuse a port already assigned elsewhere in the file
+//!
+//! The function-call placeholders (all of the above except `cfg`, which is
+//! resolved in separate passes) are dispatched through a single pluggable
+//! function table ([`TEMPLATE_FUNCTIONS`]) rather than one regex per function,
+//! so adding a new `{{ name(...) }}` placeholder doesn't require touching the
+//! parser.
+//!
+//! Port assignment can draw on a [`PortRegistry`] for durable,
+//! cross-process accounting instead of a caller-supplied `used_ports` set -
+//! see [`process_env_vibe_template_with_registry`]. [`watch_env_vibe`] keeps
+//! a generated `.env` in sync with its template as the template is edited.
 
 use std::{
     collections::{HashMap, HashSet},
+    fs::OpenOptions,
+    io::{Read, Write},
     net::TcpListener,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, RecvTimeoutError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use fs2::FileExt;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use regex::Regex;
+use rkyv::{Archive, Deserialize, Serialize};
 use thiserror::Error;
+use uuid::Uuid;
 
 #[derive(Debug, Error)]
 pub enum EnvVibeError {
@@ -18,6 +46,20 @@ pub enum EnvVibeError {
     NoAvailablePort(u32),
     #[error("Regex error: {0}")]
     Regex(#[from] regex::Error),
+    #[error("Invalid cfg() expression: {0}")]
+    InvalidCfgExpr(String),
+    #[error("Port registry I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Port registry file is corrupt: {0}")]
+    RegistryCorrupt(String),
+    #[error("File watch error: {0}")]
+    Watch(#[from] notify::Error),
+    #[error("port_from() referenced unknown variable: {0}")]
+    UnknownPortFromVar(String),
+    #[error("invalid auto_port() argument: {0}")]
+    InvalidPortPool(String),
+    #[error("no available port in {0}")]
+    PortRangeEmpty(String),
 }
 
 /// Result of processing an .env.vibe template
@@ -25,21 +67,533 @@ pub enum EnvVibeError {
 pub struct EnvVibeResult {
     /// The processed content with all placeholders replaced
     pub processed_content: String,
-    /// Map of environment variable names to their assigned ports
+    /// Map of environment variable names to their assigned ports. When a
+    /// line has more than one `auto_port()` placeholder, only the last
+    /// draw for that `KEY` is recorded here - see `all_assigned_ports` for
+    /// every port drawn while processing the file.
     pub assigned_ports: HashMap<String, u16>,
+    /// Every port drawn while processing the file, including ports from
+    /// lines with more than one `auto_port()` placeholder. This is what
+    /// callers must reserve to avoid handing the same port to another
+    /// worktree.
+    pub all_assigned_ports: HashSet<u16>,
 }
 
 const MAX_PORT_ATTEMPTS: u32 = 1000;
 pub const DEFAULT_PORT_RANGE_START: u16 = 1024;
 pub const DEFAULT_PORT_RANGE_END: u16 = 65535;
 
+/// Build the default `cfg()` evaluation context for the current build target
+/// (`target_os`, `target_arch`, `target_family`), merged with any
+/// caller-supplied custom keys. Custom keys take precedence over the
+/// defaults so callers can override them for testing.
+///
+/// Also sets the bare `unix`/`windows` identifiers Cargo's own `cfg()`
+/// grammar exposes implicitly, derived from `target_family`, so the common
+/// `{{ cfg(unix) }}` / `{{ cfg(all(unix, not(target_os = "macos"))) }}` forms
+/// work without callers having to insert those keys themselves.
+pub fn default_cfg_context(custom: &HashMap<String, String>) -> HashMap<String, String> {
+    let mut ctx = HashMap::new();
+    ctx.insert("target_os".to_string(), std::env::consts::OS.to_string());
+    ctx.insert(
+        "target_arch".to_string(),
+        std::env::consts::ARCH.to_string(),
+    );
+    let family = std::env::consts::FAMILY.to_string();
+    if family == "unix" || family == "windows" {
+        ctx.insert(family.clone(), "true".to_string());
+    }
+    ctx.insert("target_family".to_string(), family);
+    ctx.extend(custom.clone());
+    ctx
+}
+
+/// A parsed `cfg()`-style predicate, modeled on Cargo's `cfg()` grammar.
+#[derive(Debug, Clone, PartialEq)]
+enum CfgPredicate {
+    All(Vec<CfgPredicate>),
+    Any(Vec<CfgPredicate>),
+    Not(Box<CfgPredicate>),
+    /// Bare identifier: true if the key is present in the context.
+    Ident(String),
+    /// `key = "value"`: true if the key's value equals the given string.
+    Equals(String, String),
+}
+
+impl CfgPredicate {
+    fn eval(&self, ctx: &HashMap<String, String>) -> bool {
+        match self {
+            CfgPredicate::All(preds) => preds.iter().all(|p| p.eval(ctx)),
+            CfgPredicate::Any(preds) => preds.iter().any(|p| p.eval(ctx)),
+            CfgPredicate::Not(pred) => !pred.eval(ctx),
+            CfgPredicate::Ident(key) => ctx.contains_key(key),
+            CfgPredicate::Equals(key, value) => ctx.get(key) == Some(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum CfgToken {
+    Ident(String),
+    Str(String),
+    LParen,
+    RParen,
+    Comma,
+    Eq,
+}
+
+fn tokenize_cfg(input: &str) -> Result<Vec<CfgToken>, EnvVibeError> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = input.chars().collect();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(CfgToken::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(CfgToken::RParen);
+                i += 1;
+            }
+            ',' => {
+                tokens.push(CfgToken::Comma);
+                i += 1;
+            }
+            '=' => {
+                tokens.push(CfgToken::Eq);
+                i += 1;
+            }
+            '"' => {
+                let mut value = String::new();
+                i += 1;
+                let mut closed = false;
+                while i < chars.len() {
+                    if chars[i] == '"' {
+                        closed = true;
+                        i += 1;
+                        break;
+                    }
+                    value.push(chars[i]);
+                    i += 1;
+                }
+                if !closed {
+                    return Err(EnvVibeError::InvalidCfgExpr(format!(
+                        "unterminated string literal in `{input}`"
+                    )));
+                }
+                tokens.push(CfgToken::Str(value));
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push(CfgToken::Ident(chars[start..i].iter().collect()));
+            }
+            other => {
+                return Err(EnvVibeError::InvalidCfgExpr(format!(
+                    "unexpected character '{other}' in `{input}`"
+                )));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct CfgParser {
+    tokens: Vec<CfgToken>,
+    pos: usize,
+}
+
+impl CfgParser {
+    fn peek(&self) -> Option<&CfgToken> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<CfgToken> {
+        let tok = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        tok
+    }
+
+    fn expect(&mut self, expected: &CfgToken) -> Result<(), EnvVibeError> {
+        match self.next() {
+            Some(tok) if tok == *expected => Ok(()),
+            other => Err(EnvVibeError::InvalidCfgExpr(format!(
+                "expected {expected:?}, found {other:?}"
+            ))),
+        }
+    }
+
+    /// Parse a comma-separated list of predicates up to (but not consuming) `)`.
+    fn parse_list(&mut self) -> Result<Vec<CfgPredicate>, EnvVibeError> {
+        let mut preds = Vec::new();
+        if matches!(self.peek(), Some(CfgToken::RParen)) {
+            return Ok(preds);
+        }
+        loop {
+            preds.push(self.parse_expr()?);
+            match self.peek() {
+                Some(CfgToken::Comma) => {
+                    self.next();
+                }
+                _ => break,
+            }
+        }
+        Ok(preds)
+    }
+
+    fn parse_expr(&mut self) -> Result<CfgPredicate, EnvVibeError> {
+        match self.next() {
+            Some(CfgToken::Ident(name)) => match name.as_str() {
+                "all" => {
+                    self.expect(&CfgToken::LParen)?;
+                    let list = self.parse_list()?;
+                    self.expect(&CfgToken::RParen)?;
+                    Ok(CfgPredicate::All(list))
+                }
+                "any" => {
+                    self.expect(&CfgToken::LParen)?;
+                    let list = self.parse_list()?;
+                    self.expect(&CfgToken::RParen)?;
+                    Ok(CfgPredicate::Any(list))
+                }
+                "not" => {
+                    self.expect(&CfgToken::LParen)?;
+                    let inner = self.parse_expr()?;
+                    self.expect(&CfgToken::RParen)?;
+                    Ok(CfgPredicate::Not(Box::new(inner)))
+                }
+                ident => {
+                    if matches!(self.peek(), Some(CfgToken::Eq)) {
+                        self.next();
+                        match self.next() {
+                            Some(CfgToken::Str(value)) => {
+                                Ok(CfgPredicate::Equals(ident.to_string(), value))
+                            }
+                            other => Err(EnvVibeError::InvalidCfgExpr(format!(
+                                "expected string literal after '=', found {other:?}"
+                            ))),
+                        }
+                    } else {
+                        Ok(CfgPredicate::Ident(ident.to_string()))
+                    }
+                }
+            },
+            other => Err(EnvVibeError::InvalidCfgExpr(format!(
+                "expected identifier, found {other:?}"
+            ))),
+        }
+    }
+}
+
+/// Parse a `cfg()` predicate expression, e.g. `all(unix, not(target_os = "macos"))`.
+fn parse_cfg_predicate(input: &str) -> Result<CfgPredicate, EnvVibeError> {
+    let tokens = tokenize_cfg(input)?;
+    let mut parser = CfgParser { tokens, pos: 0 };
+    let expr = parser.parse_expr()?;
+    if parser.pos != parser.tokens.len() {
+        return Err(EnvVibeError::InvalidCfgExpr(format!(
+            "unexpected trailing tokens in `{input}`"
+        )));
+    }
+    Ok(expr)
+}
+
+/// Find the index of the `)` matching the `(` at `open_idx` in `s`, accounting
+/// for nesting and ignoring parens inside double-quoted strings.
+fn find_matching_paren(s: &[char], open_idx: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut i = open_idx;
+    while i < s.len() {
+        match s[i] {
+            '"' => in_string = !in_string,
+            '(' if !in_string => depth += 1,
+            ')' if !in_string => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// If `line` (trimmed) is exactly `{{ cfg(EXPR) }}`, return the parsed predicate.
+fn parse_cfg_block_open(line: &str) -> Result<Option<CfgPredicate>, EnvVibeError> {
+    let trimmed = line.trim();
+    let Some(rest) = trimmed.strip_prefix("{{") else {
+        return Ok(None);
+    };
+    let Some(rest) = rest.trim_start().strip_prefix("cfg") else {
+        return Ok(None);
+    };
+    let chars: Vec<char> = rest.chars().collect();
+    let Some(open_idx) = chars.iter().position(|&c| c == '(') else {
+        return Ok(None);
+    };
+    // cfg must be followed by '(', with only whitespace allowed in between.
+    if chars[..open_idx].iter().any(|c| !c.is_whitespace()) {
+        return Ok(None);
+    }
+    let Some(close_idx) = find_matching_paren(&chars, open_idx) else {
+        return Ok(None);
+    };
+    let after: String = chars[close_idx + 1..].iter().collect();
+    let after = after.trim_start();
+    let Some(after) = after.strip_suffix("}}") else {
+        return Ok(None);
+    };
+    if !after.trim().is_empty() {
+        return Ok(None);
+    }
+    let expr: String = chars[open_idx + 1..close_idx].iter().collect();
+    Ok(Some(parse_cfg_predicate(&expr)?))
+}
+
+/// Whether `line` (trimmed) is exactly `{{ endcfg }}`.
+fn is_cfg_block_end(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed
+        .strip_prefix("{{")
+        .and_then(|rest| rest.strip_suffix("}}"))
+        .map(|inner| inner.trim() == "endcfg")
+        .unwrap_or(false)
+}
+
+/// If the chars starting at `start` are `cfg(EXPR) ? "A" : "B" }}` (with the
+/// leading `{{` already consumed by the caller), parse the predicate
+/// expression, both string literals, and return `(end_index, expr, true_val,
+/// false_val)` where `end_index` is the index just past the closing `}}`.
+fn parse_cfg_ternary_tail(chars: &[char], start: usize) -> Option<(usize, String, String, String)> {
+    let mut i = start;
+    i = skip_ws(chars, i);
+    i = expect_literal(chars, i, "cfg")?;
+    i = skip_ws(chars, i);
+    if chars.get(i) != Some(&'(') {
+        return None;
+    }
+    let close_idx = find_matching_paren(chars, i)?;
+    let expr: String = chars[i + 1..close_idx].iter().collect();
+
+    i = skip_ws(chars, close_idx + 1);
+    if chars.get(i) != Some(&'?') {
+        return None;
+    }
+    i = skip_ws(chars, i + 1);
+    let (true_val, next) = parse_quoted_chars(chars, i)?;
+    i = skip_ws(chars, next);
+    if chars.get(i) != Some(&':') {
+        return None;
+    }
+    i = skip_ws(chars, i + 1);
+    let (false_val, next) = parse_quoted_chars(chars, i)?;
+    i = skip_ws(chars, next);
+    i = expect_literal(chars, i, "}}")?;
+
+    Some((i, expr, true_val, false_val))
+}
+
+fn skip_ws(chars: &[char], mut i: usize) -> usize {
+    while chars.get(i).is_some_and(|c| c.is_whitespace()) {
+        i += 1;
+    }
+    i
+}
+
+/// If `chars` starting at `i` matches `literal`, return the index just past it.
+fn expect_literal(chars: &[char], i: usize, literal: &str) -> Option<usize> {
+    let lit_chars: Vec<char> = literal.chars().collect();
+    if chars.len() < i + lit_chars.len() {
+        return None;
+    }
+    if chars[i..i + lit_chars.len()] == lit_chars[..] {
+        Some(i + lit_chars.len())
+    } else {
+        None
+    }
+}
+
+/// Parse a `"..."` literal starting at `i`, returning the literal's content
+/// and the index just past the closing quote.
+fn parse_quoted_chars(chars: &[char], i: usize) -> Option<(String, usize)> {
+    if chars.get(i) != Some(&'"') {
+        return None;
+    }
+    let mut j = i + 1;
+    while chars.get(j).is_some_and(|&c| c != '"') {
+        j += 1;
+    }
+    if chars.get(j) != Some(&'"') {
+        return None;
+    }
+    let value: String = chars[i + 1..j].iter().collect();
+    Some((value, j + 1))
+}
+
+/// Resolve `{{ cfg(...) ? "A" : "B" }}` inline ternaries in `line`.
+fn resolve_cfg_ternaries(
+    line: &str,
+    cfg_context: &HashMap<String, String>,
+) -> Result<String, EnvVibeError> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut result = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        if chars[i] == '{' && chars.get(i + 1) == Some(&'{') {
+            if let Some((end, expr, true_val, false_val)) = parse_cfg_ternary_tail(&chars, i + 2) {
+                let predicate = parse_cfg_predicate(&expr)?;
+                result.push_str(if predicate.eval(cfg_context) {
+                    &true_val
+                } else {
+                    &false_val
+                });
+                i = end;
+                continue;
+            }
+        }
+        result.push(chars[i]);
+        i += 1;
+    }
+
+    Ok(result)
+}
+
+/// Shared knobs for processing an .env.vibe template, grouped into one
+/// struct so `process_env_vibe_template` and `watch_env_vibe` don't keep
+/// growing a positional parameter every time a new one is needed - this is
+/// already the second addition (`cfg_context`, then `port_pools`) after the
+/// original `port_range`.
+#[derive(Debug, Clone, Default)]
+pub struct EnvVibeConfig {
+    /// Inclusive range `auto_port()` draws from when no pool is specified.
+    pub port_range: (u16, u16),
+    /// Key/value environment used to evaluate `cfg()` directives; build one
+    /// with [`default_cfg_context`].
+    pub cfg_context: HashMap<String, String>,
+    /// Named sub-ranges `auto_port(pool = "...")` can draw from.
+    pub port_pools: HashMap<String, (u16, u16)>,
+}
+
+impl EnvVibeConfig {
+    /// A config with the given port range and no `cfg()` context or pools.
+    pub fn new(port_range: (u16, u16)) -> Self {
+        Self {
+            port_range,
+            cfg_context: HashMap::new(),
+            port_pools: HashMap::new(),
+        }
+    }
+}
+
 /// Process an .env.vibe template, replacing placeholders with values.
 pub fn process_env_vibe_template(
     content: &str,
     branch_name: &str,
     used_ports: &HashSet<u16>,
-    port_range: (u16, u16),
+    config: &EnvVibeConfig,
 ) -> Result<EnvVibeResult, EnvVibeError> {
+    let mut held_listeners: Vec<TcpListener> = Vec::new();
+    process_env_vibe_template_inner(
+        content,
+        branch_name,
+        used_ports,
+        config,
+        &HashMap::new(),
+        &mut held_listeners,
+    )
+}
+
+/// Process an .env.vibe template, drawing `used_ports` from a [`PortRegistry`]
+/// instead of a caller-supplied set, and durably committing any newly
+/// assigned ports to the registry before returning.
+///
+/// Each assigned port is kept bound (a "soft hold") until the registry
+/// write succeeds, closing the gap between probing a port as free and
+/// durably claiming it.
+pub fn process_env_vibe_template_with_registry(
+    content: &str,
+    branch_name: &str,
+    registry: &PortRegistry,
+    config: &EnvVibeConfig,
+) -> Result<EnvVibeResult, EnvVibeError> {
+    let used_ports = registry.used_ports()?;
+    let mut held_listeners: Vec<TcpListener> = Vec::new();
+
+    let result = process_env_vibe_template_inner(
+        content,
+        branch_name,
+        &used_ports,
+        config,
+        &HashMap::new(),
+        &mut held_listeners,
+    )?;
+
+    let ports: Vec<u16> = result.all_assigned_ports.iter().copied().collect();
+    registry.reserve_many(&ports, branch_name)?;
+    // Only release the soft hold once the reservation is durably committed.
+    drop(held_listeners);
+
+    Ok(result)
+}
+
+/// Core template-processing loop shared by [`process_env_vibe_template`],
+/// [`process_env_vibe_template_with_registry`], and [`watch_env_vibe`].
+///
+/// `pinned_ports` forces specific `KEY`s' `auto_port()` placeholders to reuse
+/// a previously assigned port instead of drawing a new one - used by
+/// [`watch_env_vibe`] so unchanged lines keep their port across
+/// regenerations.
+fn process_env_vibe_template_inner(
+    content: &str,
+    branch_name: &str,
+    used_ports: &HashSet<u16>,
+    config: &EnvVibeConfig,
+    pinned_ports: &HashMap<String, u16>,
+    held_listeners: &mut Vec<TcpListener>,
+) -> Result<EnvVibeResult, EnvVibeError> {
+    let cfg_context = &config.cfg_context;
+
+    // First pass: resolve `{{ cfg(...) }} ... {{ endcfg }}` blocks, dropping
+    // lines inside a block whose predicate evaluates to false. This must
+    // happen before port/branch substitution so that `auto_port()` inside a
+    // dropped block never consumes a port.
+    let mut active_stack: Vec<bool> = Vec::new();
+    let mut cfg_filtered_lines: Vec<&str> = Vec::new();
+    for line in content.lines() {
+        if let Some(predicate) = parse_cfg_block_open(line)? {
+            let parent_active = active_stack.last().copied().unwrap_or(true);
+            active_stack.push(parent_active && predicate.eval(cfg_context));
+            continue;
+        }
+        if is_cfg_block_end(line) {
+            if active_stack.pop().is_none() {
+                return Err(EnvVibeError::InvalidCfgExpr(
+                    "unmatched {{ endcfg }}".to_string(),
+                ));
+            }
+            continue;
+        }
+        if active_stack.iter().all(|&active| active) {
+            cfg_filtered_lines.push(line);
+        }
+    }
+    if !active_stack.is_empty() {
+        return Err(EnvVibeError::InvalidCfgExpr(
+            "unclosed {{ cfg(...) }} block".to_string(),
+        ));
+    }
+
     // Track ports assigned within this file to avoid duplicates
     let mut file_ports: HashSet<u16> = HashSet::new();
     // Track which env var got which port (for the result)
@@ -47,61 +601,428 @@ pub fn process_env_vibe_template(
 
     let mut result_lines: Vec<String> = Vec::new();
 
-    // Regex for auto_port(): {{ auto_port() }} or {{ auto_port() | default }}
-    let auto_port_re = Regex::new(r"\{\{\s*auto_port\(\)(?:\s*\|\s*[^}]*)?\s*\}\}")?;
-    // Regex for branch(): {{ branch() }} or {{ branch() | default }}
-    let branch_re = Regex::new(r"\{\{\s*branch\(\)(?:\s*\|\s*([^}]*))?\s*\}\}")?;
     // Regex to extract env var name from a line like KEY=value
     let env_var_re = Regex::new(r"^([A-Za-z_][A-Za-z0-9_]*)\s*=")?;
 
-    for line in content.lines() {
-        let mut processed_line = line.to_string();
-
-        // Process {{ auto_port() }} placeholders
-        while auto_port_re.is_match(&processed_line) {
-            let port = find_available_port(used_ports, &file_ports, port_range)?;
-            file_ports.insert(port);
-
-            // Try to extract the env var name for tracking
-            if let Some(caps) = env_var_re.captures(&processed_line) {
-                let var_name = caps.get(1).unwrap().as_str().to_string();
-                assigned_ports.insert(var_name, port);
-            }
-
-            // Replace only the first occurrence
-            processed_line = auto_port_re
-                .replace(&processed_line, port.to_string())
-                .to_string();
+    for line in cfg_filtered_lines {
+        let ternary_resolved = resolve_cfg_ternaries(line, cfg_context)?;
+        let var_name = env_var_re
+            .captures(&ternary_resolved)
+            .map(|caps| caps.get(1).unwrap().as_str().to_string());
+
+        let mut ctx = TemplateFnContext {
+            branch_name,
+            used_ports,
+            port_range: config.port_range,
+            port_pools: &config.port_pools,
+            pinned_ports,
+            assigned_ports: &assigned_ports,
+            file_ports: &mut file_ports,
+            held_listeners: &mut *held_listeners,
+            current_var_name: None,
+            outer_default: None,
+            line_last_port: None,
+        };
+
+        let processed_line =
+            resolve_template_calls(&ternary_resolved, var_name.as_deref(), &mut ctx)?;
+
+        if let Some((name, port)) = ctx.line_last_port.take() {
+            assigned_ports.insert(name, port);
         }
 
-        // Process {{ branch() }} placeholders
-        processed_line = branch_re
-            .replace_all(&processed_line, |caps: &regex::Captures| {
-                if !branch_name.is_empty() {
-                    branch_name.to_string()
-                } else if let Some(default) = caps.get(1) {
-                    default.as_str().trim().to_string()
-                } else {
-                    // No branch and no default - keep original
-                    caps.get(0).unwrap().as_str().to_string()
-                }
-            })
-            .to_string();
-
         result_lines.push(processed_line);
     }
 
     Ok(EnvVibeResult {
         processed_content: result_lines.join("\n"),
         assigned_ports,
+        all_assigned_ports: file_ports,
+    })
+}
+
+/// Per-call context threaded through a [`TemplateFn`], bundling the
+/// read-only template inputs and the mutable port bookkeeping a function may
+/// need.
+struct TemplateFnContext<'a> {
+    branch_name: &'a str,
+    used_ports: &'a HashSet<u16>,
+    port_range: (u16, u16),
+    /// Globally-configured ranges for named pools, for `auto_port(pool="...")`.
+    port_pools: &'a HashMap<String, (u16, u16)>,
+    pinned_ports: &'a HashMap<String, u16>,
+    /// Ports assigned so far elsewhere in this file, for `port_from()`.
+    assigned_ports: &'a HashMap<String, u16>,
+    file_ports: &'a mut HashSet<u16>,
+    held_listeners: &'a mut Vec<TcpListener>,
+    /// The `KEY` of the `KEY=value` line currently being processed, if any.
+    current_var_name: Option<String>,
+    /// The `| default` fallback trailing the current placeholder, if any.
+    outer_default: Option<String>,
+    /// Set by `auto_port()` so the caller can record `KEY -> port`.
+    line_last_port: Option<(String, u16)>,
+}
+
+/// A template function implementation. Returns `Ok(None)` when the function
+/// has nothing to substitute (e.g. `branch()` with no branch and no
+/// default); the caller then uses the placeholder's own `| default` if it
+/// has one, or else leaves the matched `{{ ... }}` untouched, as it also
+/// does for an unrecognized name.
+type TemplateFn = fn(&str, &mut TemplateFnContext) -> Result<Option<String>, EnvVibeError>;
+
+/// The set of built-in template functions, keyed by name. Add an entry here
+/// to support a new `{{ name(...) }}` placeholder without touching the
+/// parsing/dispatch code.
+const TEMPLATE_FUNCTIONS: &[(&str, TemplateFn)] = &[
+    ("auto_port", tmplfn_auto_port),
+    ("branch", tmplfn_branch),
+    ("slug", tmplfn_slug),
+    ("uuid", tmplfn_uuid),
+    ("env", tmplfn_env),
+    ("port_from", tmplfn_port_from),
+];
+
+fn dispatch_template_fn(
+    name: &str,
+    args_text: &str,
+    ctx: &mut TemplateFnContext,
+) -> Result<Option<String>, EnvVibeError> {
+    match TEMPLATE_FUNCTIONS.iter().find(|(n, _)| *n == name) {
+        Some((_, f)) => f(args_text, ctx),
+        None => Ok(None),
+    }
+}
+
+/// `{{ auto_port() }}` - assign an available port, reusing a pinned port
+/// for `current_var_name` if one was supplied (see [`watch_env_vibe`]).
+fn tmplfn_auto_port(
+    args_text: &str,
+    ctx: &mut TemplateFnContext,
+) -> Result<Option<String>, EnvVibeError> {
+    let pinned = ctx
+        .current_var_name
+        .as_ref()
+        .and_then(|name| ctx.pinned_ports.get(name))
+        .copied();
+
+    let port = if let Some(pinned_port) = pinned {
+        pinned_port
+    } else {
+        let pool = resolve_auto_port_pool(args_text, ctx.port_range, ctx.port_pools)?;
+        let (port, listener) = find_available_port_held(ctx.used_ports, ctx.file_ports, pool.range)
+            .map_err(|err| match (err, &pool.label) {
+                (EnvVibeError::NoAvailablePort(_), Some(label)) => {
+                    EnvVibeError::PortRangeEmpty(label.clone())
+                }
+                (err, _) => err,
+            })?;
+        ctx.held_listeners.push(listener);
+        port
+    };
+    ctx.file_ports.insert(port);
+
+    if let Some(name) = ctx.current_var_name.clone() {
+        ctx.line_last_port = Some((name, port));
+    }
+
+    Ok(Some(port.to_string()))
+}
+
+/// A port range resolved from an `auto_port()` argument, plus a human
+/// description (`label`) used when the range is exhausted. `label` is
+/// `None` for the unconstrained, whole-`port_range` case so exhaustion
+/// there still surfaces the ordinary [`EnvVibeError::NoAvailablePort`].
+struct AutoPortPool {
+    range: (u16, u16),
+    label: Option<String>,
+}
+
+/// Resolve an `auto_port()` argument list into a concrete sub-range:
+/// - empty - the overall `port_range`
+/// - `8000-8099` - a bounded range, which must lie within `port_range`
+/// - `pool="name"` - a named pool looked up in `port_pools`, which must
+///   also lie within `port_range`
+fn resolve_auto_port_pool(
+    args_text: &str,
+    port_range: (u16, u16),
+    port_pools: &HashMap<String, (u16, u16)>,
+) -> Result<AutoPortPool, EnvVibeError> {
+    let trimmed = args_text.trim();
+    if trimmed.is_empty() {
+        return Ok(AutoPortPool {
+            range: port_range,
+            label: None,
+        });
+    }
+
+    let invalid = || EnvVibeError::InvalidPortPool(trimmed.to_string());
+
+    if let Some(rest) = trimmed.strip_prefix("pool") {
+        let rest = rest.trim_start().strip_prefix('=').ok_or_else(invalid)?;
+        let name = rest
+            .trim()
+            .strip_prefix('"')
+            .and_then(|s| s.strip_suffix('"'))
+            .ok_or_else(invalid)?;
+        let sub_range = *port_pools
+            .get(name)
+            .ok_or_else(|| EnvVibeError::InvalidPortPool(format!("unknown pool: {name}")))?;
+        validate_sub_range(sub_range, port_range)?;
+        return Ok(AutoPortPool {
+            range: sub_range,
+            label: Some(format!("pool \"{name}\" ({}-{})", sub_range.0, sub_range.1)),
+        });
+    }
+
+    let (start_text, end_text) = trimmed.split_once('-').ok_or_else(invalid)?;
+    let start: u16 = start_text.trim().parse().map_err(|_| invalid())?;
+    let end: u16 = end_text.trim().parse().map_err(|_| invalid())?;
+    if start > end {
+        return Err(invalid());
+    }
+    let sub_range = (start, end);
+    validate_sub_range(sub_range, port_range)?;
+    Ok(AutoPortPool {
+        range: sub_range,
+        label: Some(format!("{start}-{end}")),
+    })
+}
+
+fn validate_sub_range(sub_range: (u16, u16), port_range: (u16, u16)) -> Result<(), EnvVibeError> {
+    if sub_range.0 < port_range.0 || sub_range.1 > port_range.1 {
+        return Err(EnvVibeError::InvalidPortPool(format!(
+            "range {}-{} is outside the configured port range {}-{}",
+            sub_range.0, sub_range.1, port_range.0, port_range.1
+        )));
+    }
+    Ok(())
+}
+
+/// `{{ branch() }}` / `{{ branch() | default }}` - the worktree's branch
+/// name, invalid in contexts that require sanitized identifiers (see
+/// [`tmplfn_slug`]).
+fn tmplfn_branch(
+    _args_text: &str,
+    ctx: &mut TemplateFnContext,
+) -> Result<Option<String>, EnvVibeError> {
+    if !ctx.branch_name.is_empty() {
+        Ok(Some(ctx.branch_name.to_string()))
+    } else if let Some(default) = &ctx.outer_default {
+        Ok(Some(default.clone()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// `{{ slug(EXPR) }}` - sanitize `EXPR` (a literal, or a nested function
+/// call such as `branch()`) into `[a-z0-9-]` form.
+fn tmplfn_slug(
+    args_text: &str,
+    ctx: &mut TemplateFnContext,
+) -> Result<Option<String>, EnvVibeError> {
+    Ok(resolve_call_or_literal(args_text, ctx)?.map(|resolved| slugify(&resolved)))
+}
+
+fn slugify(input: &str) -> String {
+    let mut slug = String::with_capacity(input.len());
+    for c in input.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.ends_with('-') && !slug.is_empty() {
+            slug.push('-');
+        }
+    }
+    while slug.ends_with('-') {
+        slug.pop();
+    }
+    slug
+}
+
+/// `{{ uuid() }}` - a fresh random (v4) UUID.
+fn tmplfn_uuid(
+    _args_text: &str,
+    _ctx: &mut TemplateFnContext,
+) -> Result<Option<String>, EnvVibeError> {
+    Ok(Some(Uuid::new_v4().to_string()))
+}
+
+/// `{{ env(NAME | default) }}` - read `NAME` from the host environment,
+/// falling back to `default` (or leaving the placeholder untouched if
+/// neither is available).
+fn tmplfn_env(
+    args_text: &str,
+    _ctx: &mut TemplateFnContext,
+) -> Result<Option<String>, EnvVibeError> {
+    let (name, default) = match args_text.split_once('|') {
+        Some((name, default)) => (name.trim(), Some(default.trim())),
+        None => (args_text.trim(), None),
+    };
+
+    match std::env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(_) => Ok(default.map(|d| d.to_string())),
+    }
+}
+
+/// `{{ port_from(OTHER_VAR) }}` - reuse the port already assigned to
+/// `OTHER_VAR` earlier in the same file.
+fn tmplfn_port_from(
+    args_text: &str,
+    ctx: &mut TemplateFnContext,
+) -> Result<Option<String>, EnvVibeError> {
+    let name = args_text.trim();
+    match ctx.assigned_ports.get(name) {
+        Some(&port) => Ok(Some(port.to_string())),
+        None => Err(EnvVibeError::UnknownPortFromVar(name.to_string())),
+    }
+}
+
+/// If `text` (trimmed) is itself a bare `ident(...)` call, resolve it
+/// through the [`TEMPLATE_FUNCTIONS`] table and return its result verbatim
+/// (`Ok(None)` if the nested call is unresolvable - e.g. `branch()` with no
+/// branch and no default - which the caller must propagate rather than
+/// falling back to the call's own source text). Otherwise treat `text` as a
+/// literal value. Used by functions like `slug()` that take another
+/// function call as their argument (e.g. `slug(branch())`).
+fn resolve_call_or_literal(
+    text: &str,
+    ctx: &mut TemplateFnContext,
+) -> Result<Option<String>, EnvVibeError> {
+    let trimmed = text.trim();
+    let chars: Vec<char> = trimmed.chars().collect();
+
+    let mut i = 0;
+    while chars
+        .get(i)
+        .is_some_and(|c| c.is_alphanumeric() || *c == '_')
+    {
+        i += 1;
+    }
+    if i > 0 && chars.get(i) == Some(&'(') {
+        if let Some(close_idx) = find_matching_paren(&chars, i) {
+            if close_idx == chars.len() - 1 {
+                let name: String = chars[..i].iter().collect();
+                let inner_args: String = chars[i + 1..close_idx].iter().collect();
+                // A nested call has no `| default` of its own - only the
+                // outermost placeholder's default applies, and only once
+                // the whole expression is unresolvable (handled by
+                // `resolve_template_calls`). Hide the outer default from
+                // this nested dispatch so it can't leak straight through
+                // (e.g. `slug(branch()) | my-default` must not let
+                // `branch()` itself resolve to `my-default`).
+                let saved_default = ctx.outer_default.take();
+                let result = dispatch_template_fn(&name, &inner_args, ctx);
+                ctx.outer_default = saved_default;
+                return result;
+            }
+        }
+    }
+
+    Ok(Some(trimmed.to_string()))
+}
+
+/// Parsed `{{ name(args) }}` or `{{ name(args) | default }}` placeholder.
+struct TemplateCallParse {
+    /// Index just past the closing `}}`.
+    end: usize,
+    name: String,
+    args_text: String,
+    outer_default: Option<String>,
+}
+
+/// Parse a template function call starting at `start` (the first character
+/// after the placeholder's opening `{{`).
+fn parse_template_call(chars: &[char], start: usize) -> Option<TemplateCallParse> {
+    let mut i = skip_ws(chars, start);
+
+    let ident_start = i;
+    while chars
+        .get(i)
+        .is_some_and(|c| c.is_alphanumeric() || *c == '_')
+    {
+        i += 1;
+    }
+    if i == ident_start {
+        return None;
+    }
+    let name: String = chars[ident_start..i].iter().collect();
+
+    i = skip_ws(chars, i);
+    if chars.get(i) != Some(&'(') {
+        return None;
+    }
+    let close_idx = find_matching_paren(chars, i)?;
+    let args_text: String = chars[i + 1..close_idx].iter().collect();
+
+    i = skip_ws(chars, close_idx + 1);
+    let mut outer_default = None;
+    if chars.get(i) == Some(&'|') {
+        i += 1;
+        let default_start = i;
+        while chars.get(i).is_some_and(|&c| c != '}') {
+            i += 1;
+        }
+        outer_default = Some(chars[default_start..i].iter().collect::<String>());
+    }
+
+    let end = expect_literal(chars, i, "}}")?;
+    Some(TemplateCallParse {
+        end,
+        name,
+        args_text,
+        outer_default: outer_default.map(|d| d.trim().to_string()),
     })
 }
 
-fn find_available_port(
+/// Resolve every `{{ name(args) }}` template function call in `line`.
+/// `var_name` is the `KEY` of the `KEY=value` line, if any, made available
+/// to functions (like `auto_port()`) that need to know what they're
+/// assigning into.
+fn resolve_template_calls(
+    line: &str,
+    var_name: Option<&str>,
+    ctx: &mut TemplateFnContext,
+) -> Result<String, EnvVibeError> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut result = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        if chars[i] == '{' && chars.get(i + 1) == Some(&'{') {
+            if let Some(parsed) = parse_template_call(&chars, i + 2) {
+                ctx.current_var_name = var_name.map(|s| s.to_string());
+                ctx.outer_default = parsed.outer_default.clone();
+                match dispatch_template_fn(&parsed.name, &parsed.args_text, ctx)? {
+                    Some(value) => result.push_str(&value),
+                    // Unresolvable (e.g. `branch()` with no branch, or a
+                    // `slug(...)` wrapping one): fall back to this
+                    // placeholder's own `| default` if it has one,
+                    // otherwise leave the original text untouched.
+                    None => match &parsed.outer_default {
+                        Some(default) => result.push_str(default),
+                        None => result.extend(&chars[i..parsed.end]),
+                    },
+                }
+                i = parsed.end;
+                continue;
+            }
+        }
+        result.push(chars[i]);
+        i += 1;
+    }
+
+    Ok(result)
+}
+
+/// Find an available port and return it together with the `TcpListener`
+/// still bound to it (a "soft hold"). Keeping the socket open until the
+/// caller durably commits the reservation closes the window between
+/// probing a port as free and another process grabbing it.
+fn find_available_port_held(
     used_ports: &HashSet<u16>,
     file_ports: &HashSet<u16>,
     port_range: (u16, u16),
-) -> Result<u16, EnvVibeError> {
+) -> Result<(u16, TcpListener), EnvVibeError> {
     use rand::Rng;
     let mut rng = rand::rng();
 
@@ -118,9 +1039,10 @@ fn find_available_port(
             continue;
         }
 
-        // Check system availability via socket binding
-        if is_port_available(port) {
-            return Ok(port);
+        // Check system availability via socket binding, keeping the
+        // listener bound as the soft hold.
+        if let Ok(listener) = TcpListener::bind(("127.0.0.1", port)) {
+            return Ok((port, listener));
         }
     }
 
@@ -128,14 +1050,392 @@ fn find_available_port(
 }
 
 /// Check if a port is available by attempting to bind to it.
+#[cfg(test)]
 fn is_port_available(port: u16) -> bool {
     TcpListener::bind(("127.0.0.1", port)).is_ok()
 }
 
+/// A single port reservation persisted in a [`PortRegistry`] file.
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[archive(check_bytes)]
+pub struct PortReservation {
+    pub port: u16,
+    pub branch: String,
+    pub reserved_at_unix: u64,
+}
+
+/// On-disk registry of port reservations, shared across worktrees so that
+/// port assignment reflects durable state rather than an in-memory
+/// `used_ports` set passed in ad hoc.
+///
+/// The registry file holds an rkyv-serialized `Vec<PortReservation>`; reads
+/// and writes take a shared/exclusive lock on a sibling `.lock` file (see
+/// `lock_path`) so concurrent
+/// worktrees can't race each other into double-reserving a port.
+pub struct PortRegistry {
+    path: PathBuf,
+}
+
+impl PortRegistry {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Path of the lock file guarding reads/writes of `self.path`. Locking
+    /// is done against this separate, stable file rather than the data
+    /// file itself, since the data file is replaced via rename on every
+    /// write (see `write_atomic`) and a lock held on an old inode would
+    /// stop protecting anything the moment it's unlinked from the path.
+    fn lock_path(&self) -> PathBuf {
+        let file_name = self
+            .path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("registry");
+        self.path.with_file_name(format!("{file_name}.lock"))
+    }
+
+    fn tmp_path(&self) -> PathBuf {
+        let file_name = self
+            .path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("registry");
+        self.path.with_file_name(format!("{file_name}.tmp"))
+    }
+
+    fn open_lock_file(&self) -> Result<std::fs::File, EnvVibeError> {
+        Ok(OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.lock_path())?)
+    }
+
+    fn read_current(&self) -> Result<Vec<PortReservation>, EnvVibeError> {
+        let Ok(mut file) = OpenOptions::new().read(true).open(&self.path) else {
+            return Ok(Vec::new());
+        };
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        if bytes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let archived = rkyv::check_archived_root::<Vec<PortReservation>>(&bytes)
+            .map_err(|e| EnvVibeError::RegistryCorrupt(e.to_string()))?;
+        archived
+            .deserialize(&mut rkyv::Infallible)
+            .map_err(|_: std::convert::Infallible| {
+                EnvVibeError::RegistryCorrupt("failed to deserialize registry".to_string())
+            })
+    }
+
+    /// Durably replace the registry's contents with `reservations` by
+    /// writing to a sibling temp file and renaming it over `self.path`,
+    /// rather than truncating `self.path` in place - so a crash mid-write
+    /// can never leave the registry with less than the previous durable
+    /// state.
+    fn write_atomic(&self, reservations: &[PortReservation]) -> Result<(), EnvVibeError> {
+        let serialized = rkyv::to_bytes::<_, 256>(&reservations.to_vec())
+            .map_err(|e| EnvVibeError::RegistryCorrupt(e.to_string()))?;
+        let tmp_path = self.tmp_path();
+        let mut tmp_file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp_file.write_all(&serialized)?;
+        tmp_file.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Load all current reservations.
+    pub fn load(&self) -> Result<Vec<PortReservation>, EnvVibeError> {
+        let lock_file = self.open_lock_file()?;
+        lock_file.lock_shared()?;
+        let reservations = self.read_current();
+        FileExt::unlock(&lock_file)?;
+        reservations
+    }
+
+    /// Ports currently held by any branch, suitable as a `used_ports` set.
+    pub fn used_ports(&self) -> Result<HashSet<u16>, EnvVibeError> {
+        Ok(self.load()?.into_iter().map(|r| r.port).collect())
+    }
+
+    /// Durably reserve `ports` for `branch`, under an exclusive lock.
+    pub fn reserve_many(&self, ports: &[u16], branch: &str) -> Result<(), EnvVibeError> {
+        if ports.is_empty() {
+            return Ok(());
+        }
+        let lock_file = self.open_lock_file()?;
+        lock_file.lock_exclusive()?;
+
+        let mut reservations = self.read_current()?;
+        let reserved_at_unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        reservations.extend(ports.iter().map(|&port| PortReservation {
+            port,
+            branch: branch.to_string(),
+            reserved_at_unix,
+        }));
+        let result = self.write_atomic(&reservations);
+        FileExt::unlock(&lock_file)?;
+        result
+    }
+
+    /// Release every reservation owned by `branch` (called when a worktree
+    /// is torn down), returning the ports that were freed.
+    pub fn release(&self, branch: &str) -> Result<Vec<u16>, EnvVibeError> {
+        let lock_file = self.open_lock_file()?;
+        lock_file.lock_exclusive()?;
+
+        let reservations = self.read_current()?;
+        let (released, kept): (Vec<_>, Vec<_>) =
+            reservations.into_iter().partition(|r| r.branch == branch);
+        let result = self.write_atomic(&kept);
+        FileExt::unlock(&lock_file)?;
+        result?;
+
+        Ok(released.into_iter().map(|r| r.port).collect())
+    }
+
+    /// Release just `ports` (regardless of owning branch), for a worktree
+    /// that's re-porting a single `auto_port()` line rather than tearing
+    /// down entirely - see [`watch_env_vibe`].
+    pub fn release_ports(&self, ports: &[u16]) -> Result<(), EnvVibeError> {
+        if ports.is_empty() {
+            return Ok(());
+        }
+        let lock_file = self.open_lock_file()?;
+        lock_file.lock_exclusive()?;
+
+        let reservations = self.read_current()?;
+        let kept: Vec<_> = reservations
+            .into_iter()
+            .filter(|r| !ports.contains(&r.port))
+            .collect();
+        let result = self.write_atomic(&kept);
+        FileExt::unlock(&lock_file)?;
+        result
+    }
+}
+
+/// How long to wait after the last filesystem event on a watched template
+/// before regenerating, so a burst of saves collapses into one regeneration.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Structured summary of what changed in the regenerated `.env` after a
+/// `.env.vibe` edit, as reported by [`watch_env_vibe`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WatchRegenerateResult {
+    /// Variables newly introduced in this revision of the template.
+    pub added: Vec<String>,
+    /// Variables that disappeared from this revision of the template.
+    pub removed: Vec<String>,
+    /// Variables whose `auto_port()` placeholder got a new port because the
+    /// line defining them changed: `(name, old_port, new_port)`.
+    pub re_ported: Vec<(String, u16, u16)>,
+}
+
+/// Find `KEY`s whose `auto_port()`-bearing line is byte-identical between
+/// `old_content` and `new_content`, mapped to the port that key was
+/// previously assigned. Feeding this into `process_env_vibe_template_inner`
+/// as `pinned_ports` means a template tweak only reallocates ports for the
+/// lines that actually changed.
+fn pin_unchanged_ports(
+    old_content: &str,
+    new_content: &str,
+    old_assigned_ports: &HashMap<String, u16>,
+) -> HashMap<String, u16> {
+    let env_var_re = Regex::new(r"^([A-Za-z_][A-Za-z0-9_]*)\s*=").expect("static regex is valid");
+    let old_lines_by_key: HashMap<&str, &str> = old_content
+        .lines()
+        .filter_map(|line| {
+            env_var_re
+                .captures(line)
+                .map(|caps| (caps.get(1).unwrap().as_str(), line))
+        })
+        .collect();
+
+    let mut pinned = HashMap::new();
+    for line in new_content.lines() {
+        let Some(caps) = env_var_re.captures(line) else {
+            continue;
+        };
+        let key = caps.get(1).unwrap().as_str();
+        if let (Some(&old_line), Some(&port)) =
+            (old_lines_by_key.get(key), old_assigned_ports.get(key))
+        {
+            if old_line == line {
+                pinned.insert(key.to_string(), port);
+            }
+        }
+    }
+    pinned
+}
+
+/// Diff two successive `assigned_ports` maps into a structured summary of
+/// added, removed, and re-ported variables.
+fn diff_assigned_ports(
+    old_assigned_ports: &HashMap<String, u16>,
+    new_assigned_ports: &HashMap<String, u16>,
+) -> WatchRegenerateResult {
+    let mut result = WatchRegenerateResult::default();
+
+    for (key, &new_port) in new_assigned_ports {
+        match old_assigned_ports.get(key) {
+            None => result.added.push(key.clone()),
+            Some(&old_port) if old_port != new_port => {
+                result.re_ported.push((key.clone(), old_port, new_port))
+            }
+            Some(_) => {}
+        }
+    }
+    for key in old_assigned_ports.keys() {
+        if !new_assigned_ports.contains_key(key) {
+            result.removed.push(key.clone());
+        }
+    }
+
+    result.added.sort();
+    result.removed.sort();
+    result.re_ported.sort_by(|a, b| a.0.cmp(&b.0));
+    result
+}
+
+/// Watch `template_path` for changes and regenerate `output_path` each time
+/// it's edited, debouncing rapid successive edits and preserving previously
+/// assigned ports for `KEY=...auto_port()...` lines that are byte-identical
+/// to the previous revision (see `pin_unchanged_ports`), so a template
+/// tweak doesn't reshuffle every service's port.
+///
+/// Blocks the calling thread for as long as `template_path` exists; run it
+/// on a dedicated thread. Returns once the watched file is deleted or the
+/// underlying watch channel is closed.
+pub fn watch_env_vibe(
+    template_path: &Path,
+    output_path: &Path,
+    branch_name: &str,
+    registry: &PortRegistry,
+    config: &EnvVibeConfig,
+    mut on_regenerate: impl FnMut(&WatchRegenerateResult),
+) -> Result<(), EnvVibeError> {
+    let (tx, rx) = channel::<notify::Result<Event>>();
+    let mut watcher: RecommendedWatcher = notify::recommended_watcher(tx)?;
+    watcher.watch(template_path, RecursiveMode::NonRecursive)?;
+
+    // Process the template and commit/write the result, reserving every
+    // port drawn that wasn't already pinned. Shared by the initial
+    // generation below and each steady-state regeneration in the loop, so
+    // the first `.env` a caller sees was produced by the exact same
+    // pinning/registry-commit logic as every subsequent regeneration.
+    let regenerate =
+        |content: &str, pinned: &HashMap<String, u16>| -> Result<EnvVibeResult, EnvVibeError> {
+            let used_ports = registry.used_ports()?;
+            let mut held: Vec<TcpListener> = Vec::new();
+            let result = process_env_vibe_template_inner(
+                content,
+                branch_name,
+                &used_ports,
+                config,
+                pinned,
+                &mut held,
+            )?;
+
+            let newly_assigned: Vec<u16> = result
+                .all_assigned_ports
+                .iter()
+                .copied()
+                .filter(|port| !pinned.values().any(|pinned_port| pinned_port == port))
+                .collect();
+            registry.reserve_many(&newly_assigned, branch_name)?;
+            drop(held);
+
+            std::fs::write(output_path, &result.processed_content)?;
+            Ok(result)
+        };
+
+    let mut last_content = std::fs::read_to_string(template_path)?;
+    let initial_result = regenerate(&last_content, &HashMap::new())?;
+    let mut last_assigned_ports = initial_result.assigned_ports;
+    on_regenerate(&diff_assigned_ports(&HashMap::new(), &last_assigned_ports));
+
+    let mut pending_since: Option<Instant> = None;
+
+    loop {
+        let timeout = match pending_since {
+            Some(since) => WATCH_DEBOUNCE.saturating_sub(since.elapsed()),
+            None => Duration::from_secs(3600),
+        };
+
+        match rx.recv_timeout(timeout) {
+            Ok(Ok(event)) if matches!(event.kind, EventKind::Remove(_)) => {
+                return Ok(());
+            }
+            Ok(Ok(event)) if matches!(event.kind, EventKind::Modify(_) | EventKind::Create(_)) => {
+                pending_since.get_or_insert_with(Instant::now);
+                continue;
+            }
+            Ok(Ok(_)) => continue,
+            Ok(Err(err)) => return Err(EnvVibeError::Watch(err)),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+
+        let Some(since) = pending_since else {
+            continue;
+        };
+        if since.elapsed() < WATCH_DEBOUNCE {
+            continue;
+        }
+        pending_since = None;
+
+        if !template_path.exists() {
+            return Ok(());
+        }
+        let new_content = std::fs::read_to_string(template_path)?;
+        if new_content == last_content {
+            continue;
+        }
+
+        let pinned = pin_unchanged_ports(&last_content, &new_content, &last_assigned_ports);
+        let result = regenerate(&new_content, &pinned)?;
+
+        let summary = diff_assigned_ports(&last_assigned_ports, &result.assigned_ports);
+        // Release the stale port each re-ported line left behind, so an
+        // active editing session doesn't leak a reservation per edit and
+        // slowly exhaust `port_range`/a narrow pool.
+        let stale_ports: Vec<u16> = summary
+            .re_ported
+            .iter()
+            .map(|(_, old_port, _)| *old_port)
+            .collect();
+        registry.release_ports(&stale_ports)?;
+        on_regenerate(&summary);
+
+        last_content = new_content;
+        last_assigned_ports = result.assigned_ports;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn no_cfg() -> HashMap<String, String> {
+        HashMap::new()
+    }
+
+    fn no_pools() -> HashMap<String, (u16, u16)> {
+        HashMap::new()
+    }
+
     #[test]
     fn test_auto_port_replacement() {
         let content = "WEB_PORT={{ auto_port() }}";
@@ -145,7 +1445,7 @@ mod tests {
             content,
             "main",
             &used_ports,
-            (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
         )
         .unwrap();
 
@@ -170,7 +1470,7 @@ mod tests {
             content,
             "main",
             &used_ports,
-            (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
         )
         .unwrap();
 
@@ -193,7 +1493,7 @@ mod tests {
             content,
             "main",
             &used_ports,
-            (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
         )
         .unwrap();
 
@@ -222,7 +1522,7 @@ mod tests {
             content,
             "main",
             &used_ports,
-            (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
         )
         .unwrap();
 
@@ -241,7 +1541,7 @@ mod tests {
             content,
             "vk/feature-branch",
             &used_ports,
-            (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
         )
         .unwrap();
 
@@ -258,7 +1558,7 @@ mod tests {
             content,
             "vk/my-branch",
             &used_ports,
-            (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
         )
         .unwrap();
 
@@ -272,30 +1572,232 @@ mod tests {
 
         let result = process_env_vibe_template(
             content,
-            "",
+            "",
+            &used_ports,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        )
+        .unwrap();
+
+        assert_eq!(result.processed_content, "ENV=production");
+    }
+
+    #[test]
+    fn test_branch_without_default_keeps_placeholder_when_empty() {
+        let content = "ENV={{ branch() }}";
+        let used_ports = HashSet::new();
+
+        let result = process_env_vibe_template(
+            content,
+            "",
+            &used_ports,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        )
+        .unwrap();
+
+        // With no branch and no default, keep the placeholder
+        assert_eq!(result.processed_content, "ENV={{ branch() }}");
+    }
+
+    #[test]
+    fn test_slug_sanitizes_branch() {
+        let content = "DB_NAME=myapp_{{ slug(branch()) }}";
+        let used_ports = HashSet::new();
+
+        let result = process_env_vibe_template(
+            content,
+            "vk/feature-branch",
+            &used_ports,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        )
+        .unwrap();
+
+        assert_eq!(result.processed_content, "DB_NAME=myapp_vk-feature-branch");
+    }
+
+    #[test]
+    fn test_slug_of_unresolvable_nested_call_leaves_placeholder() {
+        let content = "DB_NAME={{ slug(branch()) }}";
+        let used_ports = HashSet::new();
+
+        let result = process_env_vibe_template(
+            content,
+            "",
+            &used_ports,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        )
+        .unwrap();
+
+        // branch() has no branch and no default, so it's unresolvable -
+        // slug() must not fall back to slugifying its own source text.
+        assert_eq!(result.processed_content, "DB_NAME={{ slug(branch()) }}");
+    }
+
+    #[test]
+    fn test_slug_of_unresolvable_nested_call_falls_back_to_outer_default() {
+        let content = "DB_NAME={{ slug(branch()) | my-default }}";
+        let used_ports = HashSet::new();
+
+        let result = process_env_vibe_template(
+            content,
+            "",
+            &used_ports,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        )
+        .unwrap();
+
+        // The outer `| my-default` belongs to the whole `slug(...)`
+        // expression, not to the nested branch() call - it must not leak
+        // into branch()'s own resolution, only apply once slug() itself
+        // is unresolvable.
+        assert_eq!(result.processed_content, "DB_NAME=my-default");
+    }
+
+    #[test]
+    fn test_slug_of_literal_lowercases_and_collapses_punctuation() {
+        let content = "SLUG={{ slug(Hello, World!!) }}";
+        let used_ports = HashSet::new();
+
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &used_ports,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        )
+        .unwrap();
+
+        assert_eq!(result.processed_content, "SLUG=hello-world");
+    }
+
+    #[test]
+    fn test_uuid_produces_valid_v4() {
+        let content = "REQUEST_ID={{ uuid() }}";
+        let used_ports = HashSet::new();
+
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &used_ports,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        )
+        .unwrap();
+
+        let value = result
+            .processed_content
+            .strip_prefix("REQUEST_ID=")
+            .unwrap();
+        assert_eq!(value.len(), 36);
+        assert_eq!(value.chars().filter(|&c| c == '-').count(), 4);
+    }
+
+    #[test]
+    fn test_uuid_is_fresh_each_call() {
+        let content = "A={{ uuid() }}\nB={{ uuid() }}";
+        let used_ports = HashSet::new();
+
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &used_ports,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        )
+        .unwrap();
+
+        let mut lines = result.processed_content.lines();
+        let a = lines.next().unwrap().strip_prefix("A=").unwrap();
+        let b = lines.next().unwrap().strip_prefix("B=").unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_env_reads_host_variable() {
+        std::env::set_var("ENV_VIBE_TEST_VAR", "from-host");
+        let content = "VAL={{ env(ENV_VIBE_TEST_VAR | fallback) }}";
+        let used_ports = HashSet::new();
+
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &used_ports,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        )
+        .unwrap();
+        std::env::remove_var("ENV_VIBE_TEST_VAR");
+
+        assert_eq!(result.processed_content, "VAL=from-host");
+    }
+
+    #[test]
+    fn test_env_falls_back_when_unset() {
+        std::env::remove_var("ENV_VIBE_TEST_MISSING_VAR");
+        let content = "VAL={{ env(ENV_VIBE_TEST_MISSING_VAR | fallback) }}";
+        let used_ports = HashSet::new();
+
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &used_ports,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        )
+        .unwrap();
+
+        assert_eq!(result.processed_content, "VAL=fallback");
+    }
+
+    #[test]
+    fn test_env_without_default_keeps_placeholder_when_unset() {
+        std::env::remove_var("ENV_VIBE_TEST_MISSING_VAR");
+        let content = "VAL={{ env(ENV_VIBE_TEST_MISSING_VAR) }}";
+        let used_ports = HashSet::new();
+
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &used_ports,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        )
+        .unwrap();
+
+        assert_eq!(
+            result.processed_content,
+            "VAL={{ env(ENV_VIBE_TEST_MISSING_VAR) }}"
+        );
+    }
+
+    #[test]
+    fn test_port_from_reuses_earlier_assignment() {
+        let content =
+            "API_PORT={{ auto_port() }}\nAPI_URL=http://localhost:{{ port_from(API_PORT) }}";
+        let used_ports = HashSet::new();
+
+        let result = process_env_vibe_template(
+            content,
+            "main",
             &used_ports,
-            (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
         )
         .unwrap();
 
-        assert_eq!(result.processed_content, "ENV=production");
+        let port = result.assigned_ports["API_PORT"];
+        let expected_url = format!("API_URL=http://localhost:{port}");
+        assert!(result.processed_content.contains(&expected_url));
     }
 
     #[test]
-    fn test_branch_without_default_keeps_placeholder_when_empty() {
-        let content = "ENV={{ branch() }}";
+    fn test_port_from_unknown_var_errors() {
+        let content = "API_URL=http://localhost:{{ port_from(MISSING_PORT) }}";
         let used_ports = HashSet::new();
 
         let result = process_env_vibe_template(
             content,
-            "",
+            "main",
             &used_ports,
-            (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
-        )
-        .unwrap();
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        );
 
-        // With no branch and no default, keep the placeholder
-        assert_eq!(result.processed_content, "ENV={{ branch() }}");
+        assert!(matches!(
+            result,
+            Err(EnvVibeError::UnknownPortFromVar(var)) if var == "MISSING_PORT"
+        ));
     }
 
     #[test]
@@ -307,7 +1809,7 @@ mod tests {
             content,
             "feature/login",
             &used_ports,
-            (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
         )
         .unwrap();
 
@@ -330,7 +1832,7 @@ mod tests {
             content,
             "main",
             &used_ports,
-            (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
         )
         .unwrap();
 
@@ -353,7 +1855,7 @@ mod tests {
                 content,
                 "main",
                 &HashSet::new(),
-                (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+                &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
             )
             .unwrap();
 
@@ -386,7 +1888,7 @@ mod tests {
             content,
             "main",
             &used_ports,
-            (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
         )
         .unwrap();
 
@@ -416,7 +1918,7 @@ DEBUG=true"#;
             content,
             "feature/auth",
             &HashSet::new(),
-            (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
         )
         .unwrap();
 
@@ -426,11 +1928,9 @@ DEBUG=true"#;
         assert!(result.assigned_ports.contains_key("API_PORT"));
 
         // Branch should be replaced
-        assert!(
-            result
-                .processed_content
-                .contains("DB_NAME=myapp_feature/auth")
-        );
+        assert!(result
+            .processed_content
+            .contains("DB_NAME=myapp_feature/auth"));
 
         // No placeholders should remain
         assert!(!result.processed_content.contains("{{ auto_port()"));
@@ -447,7 +1947,7 @@ DEBUG=true"#;
             content,
             "main",
             &used_ports,
-            (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
         )
         .unwrap();
 
@@ -465,4 +1965,581 @@ DEBUG=true"#;
         let port2: u16 = port_parts[1].parse().unwrap();
         assert_ne!(port1, port2); // Should be different ports
     }
+
+    #[test]
+    fn test_auto_port_bounded_range() {
+        let content = "WEB_PORT={{ auto_port(8000-8099) }}";
+        let used_ports = HashSet::new();
+
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &used_ports,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        )
+        .unwrap();
+
+        let port = result.assigned_ports["WEB_PORT"];
+        assert!((8000..=8099).contains(&port));
+    }
+
+    #[test]
+    fn test_auto_port_named_pool() {
+        let content = "FRONTEND_PORT={{ auto_port(pool=\"frontend\") }}";
+        let used_ports = HashSet::new();
+        let mut pools = HashMap::new();
+        pools.insert("frontend".to_string(), (9000u16, 9010u16));
+
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &used_ports,
+            &EnvVibeConfig {
+                port_range: (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+                cfg_context: no_cfg(),
+                port_pools: pools.clone(),
+            },
+        )
+        .unwrap();
+
+        let port = result.assigned_ports["FRONTEND_PORT"];
+        assert!((9000..=9010).contains(&port));
+    }
+
+    #[test]
+    fn test_auto_port_unknown_pool_errors() {
+        let content = "PORT={{ auto_port(pool=\"nope\") }}";
+        let used_ports = HashSet::new();
+
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &used_ports,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        );
+
+        assert!(matches!(result, Err(EnvVibeError::InvalidPortPool(_))));
+    }
+
+    #[test]
+    fn test_auto_port_range_outside_overall_range_errors() {
+        let content = "PORT={{ auto_port(100-200) }}";
+        let used_ports = HashSet::new();
+
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &used_ports,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        );
+
+        assert!(matches!(result, Err(EnvVibeError::InvalidPortPool(_))));
+    }
+
+    #[test]
+    fn test_auto_port_constrained_range_exhausted_returns_port_range_empty() {
+        let content = "A={{ auto_port(9500-9500) }}\nB={{ auto_port(9500-9500) }}";
+        let used_ports = HashSet::new();
+
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &used_ports,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        );
+
+        assert!(matches!(result, Err(EnvVibeError::PortRangeEmpty(_))));
+    }
+
+    #[test]
+    fn test_cfg_block_true_keeps_lines() {
+        let mut ctx = HashMap::new();
+        ctx.insert("target_os".to_string(), "linux".to_string());
+        ctx.insert("unix".to_string(), "true".to_string());
+        let content = "{{ cfg(unix) }}\nLOG_DRIVER=journald\n{{ endcfg }}\nHOST=localhost";
+
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &HashSet::new(),
+            &EnvVibeConfig {
+                port_range: (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+                cfg_context: ctx.clone(),
+                port_pools: no_pools(),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            result.processed_content,
+            "LOG_DRIVER=journald\nHOST=localhost"
+        );
+    }
+
+    #[test]
+    fn test_cfg_block_false_drops_lines_and_skips_ports() {
+        let ctx = HashMap::new();
+        let content =
+            "{{ cfg(macos) }}\nDEBUG_PORT={{ auto_port() }}\n{{ endcfg }}\nHOST=localhost";
+
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &HashSet::new(),
+            &EnvVibeConfig {
+                port_range: (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+                cfg_context: ctx.clone(),
+                port_pools: no_pools(),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(result.processed_content, "HOST=localhost");
+        assert!(!result.assigned_ports.contains_key("DEBUG_PORT"));
+    }
+
+    #[test]
+    fn test_cfg_all_any_not() {
+        let mut ctx = HashMap::new();
+        ctx.insert("target_os".to_string(), "linux".to_string());
+        ctx.insert("target_family".to_string(), "unix".to_string());
+        ctx.insert("unix".to_string(), "true".to_string());
+
+        let content = "{{ cfg(all(unix, not(target_os = \"macos\"))) }}\nA=1\n{{ endcfg }}";
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &HashSet::new(),
+            &EnvVibeConfig {
+                port_range: (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+                cfg_context: ctx.clone(),
+                port_pools: no_pools(),
+            },
+        )
+        .unwrap();
+        assert_eq!(result.processed_content, "A=1");
+
+        let content =
+            "{{ cfg(any(target_os = \"macos\", target_os = \"windows\")) }}\nB=1\n{{ endcfg }}";
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &HashSet::new(),
+            &EnvVibeConfig {
+                port_range: (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+                cfg_context: ctx.clone(),
+                port_pools: no_pools(),
+            },
+        )
+        .unwrap();
+        assert_eq!(result.processed_content, "");
+    }
+
+    #[test]
+    fn test_cfg_nested_blocks() {
+        let mut ctx = HashMap::new();
+        ctx.insert("target_os".to_string(), "linux".to_string());
+        ctx.insert("unix".to_string(), "true".to_string());
+
+        let content = "{{ cfg(unix) }}\n{{ cfg(target_os = \"macos\") }}\nMAC_ONLY=1\n{{ endcfg }}\nUNIX_ONLY=1\n{{ endcfg }}";
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &HashSet::new(),
+            &EnvVibeConfig {
+                port_range: (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+                cfg_context: ctx.clone(),
+                port_pools: no_pools(),
+            },
+        )
+        .unwrap();
+        assert_eq!(result.processed_content, "UNIX_ONLY=1");
+    }
+
+    #[test]
+    fn test_cfg_inline_ternary() {
+        let mut ctx = HashMap::new();
+        ctx.insert("target_os".to_string(), "linux".to_string());
+
+        let content = "SHELL_PATH={{ cfg(target_os = \"macos\") ? \"/bin/zsh\" : \"/bin/bash\" }}";
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &HashSet::new(),
+            &EnvVibeConfig {
+                port_range: (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+                cfg_context: ctx.clone(),
+                port_pools: no_pools(),
+            },
+        )
+        .unwrap();
+        assert_eq!(result.processed_content, "SHELL_PATH=/bin/bash");
+    }
+
+    #[test]
+    fn test_default_cfg_context_includes_target_os() {
+        let ctx = default_cfg_context(&HashMap::new());
+        assert_eq!(
+            ctx.get("target_os").map(String::as_str),
+            Some(std::env::consts::OS)
+        );
+    }
+
+    #[test]
+    fn test_default_cfg_context_custom_keys_override() {
+        let mut custom = HashMap::new();
+        custom.insert("target_os".to_string(), "freebsd".to_string());
+        custom.insert("env".to_string(), "ci".to_string());
+
+        let ctx = default_cfg_context(&custom);
+        assert_eq!(ctx.get("target_os").map(String::as_str), Some("freebsd"));
+        assert_eq!(ctx.get("env").map(String::as_str), Some("ci"));
+    }
+
+    #[test]
+    fn test_default_cfg_context_sets_bare_unix_or_windows() {
+        let ctx = default_cfg_context(&HashMap::new());
+        let family = std::env::consts::FAMILY;
+        assert_eq!(ctx.get(family).map(String::as_str), Some("true"));
+    }
+
+    #[test]
+    fn test_cfg_unix_block_true_with_default_context() {
+        let content = "{{ cfg(unix) }}\nLOG_DRIVER=journald\n{{ endcfg }}\nHOST=localhost";
+        let ctx = default_cfg_context(&HashMap::new());
+
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &HashSet::new(),
+            &EnvVibeConfig {
+                port_range: (DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END),
+                cfg_context: ctx,
+                port_pools: no_pools(),
+            },
+        )
+        .unwrap();
+
+        if cfg!(unix) {
+            assert_eq!(
+                result.processed_content,
+                "LOG_DRIVER=journald\nHOST=localhost"
+            );
+        } else {
+            assert_eq!(result.processed_content, "HOST=localhost");
+        }
+    }
+
+    #[test]
+    fn test_cfg_unmatched_endcfg_errors() {
+        let content = "{{ endcfg }}";
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &HashSet::new(),
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_cfg_unclosed_block_errors() {
+        let content = "{{ cfg(unix) }}\nA=1";
+        let result = process_env_vibe_template(
+            content,
+            "main",
+            &HashSet::new(),
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        );
+        assert!(result.is_err());
+    }
+
+    fn temp_registry_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "env_vibe_registry_test_{name}_{}.rkyv",
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn test_registry_empty_when_missing() {
+        let registry = PortRegistry::new(temp_registry_path("missing"));
+        assert!(registry.load().unwrap().is_empty());
+        assert!(registry.used_ports().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_registry_reserve_and_load() {
+        let path = temp_registry_path("reserve");
+        let registry = PortRegistry::new(&path);
+
+        registry
+            .reserve_many(&[5000, 5001], "vk/feature-a")
+            .unwrap();
+
+        let reservations = registry.load().unwrap();
+        assert_eq!(reservations.len(), 2);
+        assert!(reservations.iter().all(|r| r.branch == "vk/feature-a"));
+
+        let used = registry.used_ports().unwrap();
+        assert!(used.contains(&5000));
+        assert!(used.contains(&5001));
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_registry_release_reclaims_only_owned_ports() {
+        let path = temp_registry_path("release");
+        let registry = PortRegistry::new(&path);
+
+        registry.reserve_many(&[6000], "vk/feature-a").unwrap();
+        registry.reserve_many(&[6001], "vk/feature-b").unwrap();
+
+        let mut released = registry.release("vk/feature-a").unwrap();
+        released.sort();
+        assert_eq!(released, vec![6000]);
+
+        let remaining = registry.used_ports().unwrap();
+        assert!(!remaining.contains(&6000));
+        assert!(remaining.contains(&6001));
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_registry_release_ports_frees_only_those_ports() {
+        let path = temp_registry_path("release_ports");
+        let registry = PortRegistry::new(&path);
+
+        registry
+            .reserve_many(&[6100, 6101], "vk/feature-a")
+            .unwrap();
+        registry.reserve_many(&[6102], "vk/feature-b").unwrap();
+
+        registry.release_ports(&[6100]).unwrap();
+
+        let remaining = registry.used_ports().unwrap();
+        assert!(!remaining.contains(&6100));
+        assert!(remaining.contains(&6101));
+        assert!(remaining.contains(&6102));
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_process_with_registry_commits_assigned_ports() {
+        let path = temp_registry_path("process");
+        let registry = PortRegistry::new(&path);
+
+        let content = "WEB_PORT={{ auto_port() }}";
+        let result = process_env_vibe_template_with_registry(
+            content,
+            "vk/feature-c",
+            &registry,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        )
+        .unwrap();
+
+        let port = result.assigned_ports["WEB_PORT"];
+        let used = registry.used_ports().unwrap();
+        assert!(used.contains(&port));
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_process_with_registry_commits_every_port_on_a_multi_port_line() {
+        let path = temp_registry_path("process_multi_port_line");
+        let registry = PortRegistry::new(&path);
+
+        // Two auto_port() calls on one line: only the second is tracked in
+        // `assigned_ports` (keyed by `PORTS`), but both must be reserved.
+        let content = "PORTS={{ auto_port() }},{{ auto_port() }}";
+        let result = process_env_vibe_template_with_registry(
+            content,
+            "vk/feature-multi",
+            &registry,
+            &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+        )
+        .unwrap();
+
+        assert_eq!(result.all_assigned_ports.len(), 2);
+        let used = registry.used_ports().unwrap();
+        for port in &result.all_assigned_ports {
+            assert!(used.contains(port));
+        }
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_pin_unchanged_ports_keeps_identical_lines() {
+        let old_content = "WEB_PORT={{ auto_port() }}\nAPI_PORT={{ auto_port() }}";
+        let new_content =
+            "WEB_PORT={{ auto_port() }}\nAPI_PORT={{ auto_port() }}\nDB_PORT={{ auto_port() }}";
+        let mut old_assigned = HashMap::new();
+        old_assigned.insert("WEB_PORT".to_string(), 4000);
+        old_assigned.insert("API_PORT".to_string(), 4001);
+
+        let pinned = pin_unchanged_ports(old_content, new_content, &old_assigned);
+
+        assert_eq!(pinned.get("WEB_PORT"), Some(&4000));
+        assert_eq!(pinned.get("API_PORT"), Some(&4001));
+        assert!(!pinned.contains_key("DB_PORT"));
+    }
+
+    #[test]
+    fn test_pin_unchanged_ports_drops_changed_lines() {
+        let old_content = "WEB_PORT={{ auto_port() }}";
+        let new_content = "WEB_PORT={{ auto_port() | 8080 }}";
+        let mut old_assigned = HashMap::new();
+        old_assigned.insert("WEB_PORT".to_string(), 4000);
+
+        let pinned = pin_unchanged_ports(old_content, new_content, &old_assigned);
+
+        assert!(!pinned.contains_key("WEB_PORT"));
+    }
+
+    #[test]
+    fn test_diff_assigned_ports_reports_added_removed_re_ported() {
+        let mut old = HashMap::new();
+        old.insert("WEB_PORT".to_string(), 4000);
+        old.insert("API_PORT".to_string(), 4001);
+
+        let mut new = HashMap::new();
+        new.insert("WEB_PORT".to_string(), 4000);
+        new.insert("API_PORT".to_string(), 5000);
+        new.insert("DB_PORT".to_string(), 4002);
+
+        let summary = diff_assigned_ports(&old, &new);
+
+        assert_eq!(summary.added, vec!["DB_PORT".to_string()]);
+        assert_eq!(summary.removed, Vec::<String>::new());
+        assert_eq!(
+            summary.re_ported,
+            vec![("API_PORT".to_string(), 4001, 5000)]
+        );
+    }
+
+    #[test]
+    fn test_watch_env_vibe_regenerates_on_change() {
+        let dir = std::env::temp_dir().join(format!(
+            "env_vibe_watch_test_{}_{}",
+            std::process::id(),
+            line!()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let template_path = dir.join(".env.vibe");
+        let output_path = dir.join(".env");
+        let registry_path = dir.join("registry.rkyv");
+        std::fs::write(&template_path, "WEB_PORT={{ auto_port() }}\n").unwrap();
+
+        let registry = PortRegistry::new(&registry_path);
+        let template_path_clone = template_path.clone();
+        let output_path_clone = output_path.clone();
+
+        let handle = std::thread::spawn(move || {
+            let mut regenerations = 0;
+            let _ = watch_env_vibe(
+                &template_path_clone,
+                &output_path_clone,
+                "vk/watch-test",
+                &registry,
+                &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+                |_summary| {
+                    regenerations += 1;
+                    // The first regeneration is the initial generate-and-write
+                    // done before the watch loop starts; only delete once the
+                    // second (edit-triggered) regeneration has happened.
+                    if regenerations >= 2 {
+                        std::fs::remove_file(&template_path_clone).ok();
+                    }
+                },
+            );
+            regenerations
+        });
+
+        // The initial generate-and-write should have already produced an
+        // `.env` with just WEB_PORT before any edit is made.
+        std::thread::sleep(Duration::from_millis(100));
+        let initial_generated = std::fs::read_to_string(&output_path).unwrap();
+        assert!(initial_generated.contains("WEB_PORT="));
+        assert!(!initial_generated.contains("API_PORT="));
+
+        std::fs::write(
+            &template_path,
+            "WEB_PORT={{ auto_port() }}\nAPI_PORT={{ auto_port() }}\n",
+        )
+        .unwrap();
+
+        let regenerations = handle.join().unwrap();
+        assert_eq!(regenerations, 2);
+
+        let generated = std::fs::read_to_string(&output_path).unwrap();
+        assert!(generated.contains("WEB_PORT="));
+        assert!(generated.contains("API_PORT="));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_watch_env_vibe_releases_stale_port_on_re_port() {
+        let dir = std::env::temp_dir().join(format!(
+            "env_vibe_watch_report_test_{}_{}",
+            std::process::id(),
+            line!()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let template_path = dir.join(".env.vibe");
+        let output_path = dir.join(".env");
+        let registry_path = dir.join("registry.rkyv");
+        std::fs::write(&template_path, "WEB_PORT={{ auto_port() }}\n").unwrap();
+
+        let registry = PortRegistry::new(&registry_path);
+        let template_path_clone = template_path.clone();
+        let output_path_clone = output_path.clone();
+
+        let handle = std::thread::spawn(move || {
+            let mut regenerations = 0;
+            let _ = watch_env_vibe(
+                &template_path_clone,
+                &output_path_clone,
+                "vk/watch-report-test",
+                &registry,
+                &EnvVibeConfig::new((DEFAULT_PORT_RANGE_START, DEFAULT_PORT_RANGE_END)),
+                |_summary| {
+                    regenerations += 1;
+                    if regenerations >= 2 {
+                        std::fs::remove_file(&template_path_clone).ok();
+                    }
+                },
+            );
+            regenerations
+        });
+
+        std::thread::sleep(Duration::from_millis(100));
+        let initial_generated = std::fs::read_to_string(&output_path).unwrap();
+        let old_port: u16 = initial_generated
+            .trim()
+            .strip_prefix("WEB_PORT=")
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        // Change a comment on the auto_port() line so it re-ports without
+        // changing the set of variables.
+        std::fs::write(&template_path, "WEB_PORT={{ auto_port() }} # moved\n").unwrap();
+
+        let regenerations = handle.join().unwrap();
+        assert_eq!(regenerations, 2);
+
+        // A fresh handle onto the same registry file should see the old
+        // port freed, not leaked forever.
+        let reopened = PortRegistry::new(&registry_path);
+        let used = reopened.used_ports().unwrap();
+        assert!(!used.contains(&old_port));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
 }